@@ -0,0 +1,28 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+/// An address a peer can be reached at.
+///
+/// Most peers are plain IP sockets, but a node may also advertise a hostname-based address (e.g.
+/// a Tor `.onion` service) that has to be resolved by whatever does the dialing rather than by
+/// standard DNS/IP routing.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PeerAddress {
+    Socket(SocketAddr),
+    Hostname { host: String, port: u16 },
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddress::Socket(a) => write!(f, "{}", a),
+            PeerAddress::Hostname { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddress {
+    fn from(a: SocketAddr) -> Self {
+        PeerAddress::Socket(a)
+    }
+}
@@ -0,0 +1,75 @@
+use sha2::digest::Digest;
+use sha2::Sha512;
+
+use crate::utils::xor_into;
+
+/// Result of a `DataStore::load()` lookup.
+pub enum LoadResult<V> {
+    Ok(V),
+    NotFound,
+    Invalid,
+}
+
+/// Result of a `DataStore::store()` write.
+pub enum StoreResult {
+    /// Stored successfully; the value is the store's revision/sequence number for the key.
+    Ok(i64),
+    /// The exact same value was already present; nothing was written.
+    Duplicate,
+    /// Value was rejected, e.g. because it failed validation.
+    Rejected,
+}
+
+/// A key/value store that can be synchronized with other nodes holding the same `domain()`.
+///
+/// Keys are always 64 bytes. Implementations need only be able to load, store, count, and iterate
+/// records by key range; the sync protocol in `node` builds range reconciliation on top of these
+/// primitives.
+pub trait DataStore: Sync + Send {
+    type LoadResultValueType: AsRef<[u8]> + Sync + Send + Clone;
+
+    /// Maximum size in bytes of a value accepted by `store()`.
+    const MAX_VALUE_SIZE: usize;
+
+    /// Number of sub-ranges a mismatching range is split into by `hash_range` based reconciliation.
+    const HASH_RANGE_FANOUT: usize = 16;
+
+    /// Below this many records a range is listed and fetched directly instead of being split further.
+    const HASH_RANGE_LEAF_THRESHOLD: u64 = 16;
+
+    /// The store's own clock, used as `reference_time` for reads that want "now".
+    fn clock(&self) -> i64;
+
+    /// A short name identifying what this store holds, used to keep distinct datasets from mixing on the wire.
+    fn domain(&self) -> &str;
+
+    fn load(&self, reference_time: i64, key: &[u8]) -> LoadResult<Self::LoadResultValueType>;
+
+    fn store(&self, key: &[u8], value: &[u8]) -> StoreResult;
+
+    fn count(&self, reference_time: i64, key_range_start: &[u8], key_range_end: &[u8]) -> u64;
+
+    fn total_count(&self) -> u64;
+
+    fn for_each<F: FnMut(&[u8], &[u8]) -> bool>(&self, reference_time: i64, key_range_start: &[u8], key_range_end: &[u8], f: F);
+
+    /// Commutative digest of every record in `[key_range_start, key_range_end]`.
+    ///
+    /// Two ranges with an identical hash are treated as holding identical contents, which lets the
+    /// sync protocol recurse only into sub-ranges whose hashes actually differ rather than comparing
+    /// record counts. Records are folded together with XOR so the result doesn't depend on iteration
+    /// order and is cheap to maintain incrementally. The default implementation folds over
+    /// `for_each`; stores that can compute this more cheaply (e.g. from a Merkle index) should
+    /// override it directly.
+    fn hash_range(&self, reference_time: i64, key_range_start: &[u8], key_range_end: &[u8]) -> [u8; 64] {
+        let mut acc = [0_u8; 64];
+        self.for_each(reference_time, key_range_start, key_range_end, |k, v| {
+            let mut h = Sha512::new();
+            h.update(k);
+            h.update(v);
+            xor_into(&mut acc, h.finalize().as_slice().try_into().unwrap());
+            true
+        });
+        acc
+    }
+}
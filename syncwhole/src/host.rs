@@ -0,0 +1,55 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::address::PeerAddress;
+use crate::node::RemoteNodeInfo;
+
+/// Callbacks and configuration a `Node` needs from its embedding application.
+pub trait Host: Sync + Send {
+    /// Peers the node should always try to stay connected to, e.g. configured bootstrap nodes.
+    fn fixed_peers(&self) -> &[PeerAddress];
+
+    /// An optional human-readable name for this node, exchanged during the connection handshake.
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// `via_proxy` reports whether this attempt is being routed through a SOCKS5 proxy per `proxy_for`.
+    fn on_connect_attempt(&self, address: &PeerAddress, via_proxy: bool);
+
+    fn on_connect(&self, info: &RemoteNodeInfo);
+
+    fn on_connection_closed(&self, info: &RemoteNodeInfo, reason: String);
+
+    /// Fill `buf` with cryptographically secure random bytes.
+    fn get_secure_random(&self, buf: &mut [u8]);
+
+    /// Called when the node learns of a peer address it didn't already know about.
+    fn on_peer_discovered(&self, _address: &PeerAddress, _last_seen_ms: i64) {}
+
+    /// How long to wait for a pong before declaring a connection dead.
+    fn keepalive_timeout(&self) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    /// This node's own connection-timeout budget (e.g. how long an upstream NAT or firewall is
+    /// expected to keep a mapping alive without traffic), advertised to peers during the handshake.
+    /// Both sides negotiate their actual keepalive interval down from the smaller of the two
+    /// advertised values, so a short-lived or NAT-bound peer can pull a well-connected one down to
+    /// match it.
+    fn connection_timeout(&self) -> Duration {
+        Duration::from_secs(300)
+    }
+
+    /// If set, outbound connections to `address` are routed through the SOCKS5 proxy at the
+    /// returned address (e.g. a local Tor daemon) instead of connecting to it directly. This is
+    /// what makes `PeerAddress::Hostname` (`.onion`) peers reachable at all.
+    fn proxy_for(&self, _address: &PeerAddress) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Gossiped peer addresses not re-confirmed within this long are dropped from the peer table.
+    fn gossip_max_age(&self) -> Duration {
+        Duration::from_secs(3600)
+    }
+}
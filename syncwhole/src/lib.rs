@@ -0,0 +1,7 @@
+pub mod address;
+pub mod datastore;
+pub mod host;
+pub mod node;
+pub mod protocol;
+pub mod socks5;
+pub mod utils;
@@ -10,6 +10,7 @@ use std::time::{Duration, Instant, SystemTime};
 use sha2::digest::Digest;
 use sha2::Sha512;
 
+use syncwhole::address::PeerAddress;
 use syncwhole::datastore::{DataStore, LoadResult, StoreResult};
 use syncwhole::host::Host;
 use syncwhole::node::{Node, RemoteNodeInfo};
@@ -38,16 +39,16 @@ fn get_random_bytes(mut buf: &mut [u8]) {
 
 struct TestNodeHost {
     name: String,
-    peers: Vec<SocketAddr>,
+    peers: Vec<PeerAddress>,
     db: Mutex<BTreeMap<[u8; 64], Arc<[u8]>>>,
 }
 
 impl Host for TestNodeHost {
-    fn fixed_peers(&self) -> &[SocketAddr] { self.peers.as_slice() }
+    fn fixed_peers(&self) -> &[PeerAddress] { self.peers.as_slice() }
 
     fn name(&self) -> Option<&str> { Some(self.name.as_str()) }
 
-    fn on_connect_attempt(&self, _address: &SocketAddr) {
+    fn on_connect_attempt(&self, _address: &PeerAddress, _via_proxy: bool) {
         //println!("{:5}: connecting to {}", self.name, _address.to_string());
     }
 
@@ -110,6 +111,19 @@ impl DataStore for TestNodeHost {
             }
         }
     }
+
+    fn hash_range(&self, _: i64, key_range_start: &[u8], key_range_end: &[u8]) -> [u8; 64] {
+        let s: [u8; 64] = key_range_start.try_into().unwrap();
+        let e: [u8; 64] = key_range_end.try_into().unwrap();
+        let mut acc = [0_u8; 64];
+        for (k, v) in self.db.lock().unwrap().range((Included(s), Included(e))) {
+            let mut h = Sha512::new();
+            h.update(k);
+            h.update(v.as_ref());
+            xor_into(&mut acc, h.finalize().as_slice().try_into().unwrap());
+        }
+        acc
+    }
 }
 
 fn main() {
@@ -120,10 +134,10 @@ fn main() {
         println!("Starting nodes on 127.0.0.1...");
         let mut nodes: Vec<Node<TestNodeHost, TestNodeHost>> = Vec::with_capacity(TEST_NODE_COUNT);
         for port in TEST_PORT_RANGE_START..(TEST_PORT_RANGE_START + (TEST_NODE_COUNT as u16)) {
-            let mut peers: Vec<SocketAddr> = Vec::with_capacity(TEST_NODE_COUNT);
+            let mut peers: Vec<PeerAddress> = Vec::with_capacity(TEST_NODE_COUNT);
             for port2 in TEST_PORT_RANGE_START..(TEST_PORT_RANGE_START + (TEST_NODE_COUNT as u16)) {
                 if port != port2 {
-                    peers.push(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port2)));
+                    peers.push(PeerAddress::Socket(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port2))));
                 }
             }
             let nh = Arc::new(TestNodeHost {
@@ -0,0 +1,915 @@
+//! Connection management and the range-reconciliation sync protocol.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::address::PeerAddress;
+use crate::datastore::{DataStore, LoadResult};
+use crate::host::Host;
+use crate::protocol::Message;
+use crate::socks5::connect_via_socks5;
+use crate::utils::ms_since_epoch;
+
+/// How often each connection re-runs full-range reconciliation with its peer.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the keepalive task checks whether a ping is due or overdue.
+const KEEPALIVE_TICK: Duration = Duration::from_secs(1);
+
+/// Timeout budget a node advertises once it has detected it's behind NAT, short enough that
+/// intermediate NAT mapping state won't expire between keepalives.
+const NAT_DETECTED_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Floor on the negotiated keepalive interval, regardless of what either side advertises.
+const MIN_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often each connection exchanges a sample of known peer addresses with its peer.
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Maximum time to wait for an outbound connect attempt (including a SOCKS5 proxy handshake) to
+/// succeed before giving up on that candidate.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of peer addresses exchanged in one gossip round.
+const GOSSIP_SAMPLE_SIZE: usize = 64;
+
+/// Extra bytes of framing/envelope overhead allowed on top of `D::MAX_VALUE_SIZE` when capping
+/// incoming frame size: the domain name, keys, and varints surrounding the largest non-`Value`
+/// message are well under this.
+const FRAME_OVERHEAD: usize = 4096;
+
+/// Information about a connection to a remote node, kept up to date as the connection lives.
+pub struct RemoteNodeInfo {
+    pub node_name: Option<String>,
+    pub remote_address: PeerAddress,
+    pub inbound: bool,
+    pub initialized: bool,
+    /// Whether this connection was dialed through a SOCKS5 proxy (always `false` for inbound connections).
+    pub via_proxy: bool,
+    /// Round-trip time of the most recent keepalive ping/pong, once one has completed.
+    pub latency: Option<Duration>,
+    /// Milliseconds since the epoch at which any data was last received from this peer.
+    pub last_seen_ms: i64,
+    /// The peer's advertised connection-timeout budget, from its `Hello`.
+    pub peer_advertised_timeout: Duration,
+    /// The keepalive interval this connection negotiated: `min(our timeout, peer timeout) / 3`,
+    /// floored at `MIN_KEEPALIVE_INTERVAL`.
+    pub negotiated_keepalive_interval: Duration,
+    /// Whether this node believes itself to be behind NAT, as reported back by this peer.
+    pub nat_detected: bool,
+}
+
+/// Keepalive bookkeeping shared between the reader loop and the keepalive task for one connection.
+struct KeepaliveState {
+    last_activity: Instant,
+    outstanding: Option<(u64, Instant)>,
+}
+
+/// The in-flight reply slot for one connection's outstanding request/response round trip.
+///
+/// A connection has exactly one request outstanding at a time, but `sync_task`, `gossip_task`, and
+/// `Node::request` can all try to issue one concurrently. `serialize` is held for the full duration
+/// of `request()` — not just while touching `slot` — so a second caller blocks until the first has
+/// gotten its reply or timed out, instead of clobbering its oneshot sender in `slot`.
+struct PendingResponse {
+    serialize: tokio::sync::Mutex<()>,
+    slot: Mutex<Option<oneshot::Sender<Message>>>,
+}
+
+impl PendingResponse {
+    fn new() -> Self {
+        Self { serialize: tokio::sync::Mutex::new(()), slot: Mutex::new(None) }
+    }
+
+    /// Take the pending reply sender, if any, for the reader task to deliver a reply into.
+    fn take(&self) -> Option<oneshot::Sender<Message>> {
+        self.slot.lock().unwrap().take()
+    }
+}
+
+struct ConnectionHandle {
+    info: Arc<Mutex<RemoteNodeInfo>>,
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    pending_response: Arc<PendingResponse>,
+}
+
+/// State shared by every background task and connection belonging to one `Node`, so they can be
+/// handed around as a single `Arc` instead of as a growing list of individually cloned fields.
+struct NodeContext<H: Host + 'static, D: DataStore + 'static> {
+    host: Arc<H>,
+    datastore: Arc<D>,
+    bind_address: SocketAddr,
+    connections: Mutex<HashMap<PeerAddress, ConnectionHandle>>,
+    /// Set once a peer reports observing this node at an address other than `bind_address`.
+    nat_detected: std::sync::atomic::AtomicBool,
+    /// The connection-timeout budget this node currently advertises to new peers; shortened once
+    /// `nat_detected` is set.
+    advertised_timeout: Mutex<Duration>,
+    /// Peer addresses known to this node, whether configured or learned by gossip, each tagged with
+    /// when it was last seen.
+    peer_table: Mutex<HashMap<PeerAddress, i64>>,
+    /// Peer addresses the dial loop currently has a connection attempt in flight for. Without this,
+    /// the loop's next 1-second tick would see the address still missing from `connections` (the
+    /// handshake hasn't registered it yet) and dial it again.
+    dialing: Mutex<std::collections::HashSet<PeerAddress>>,
+}
+
+/// A syncwhole node: manages connections to peers and keeps `D` reconciled with theirs.
+pub struct Node<H: Host + 'static, D: DataStore + 'static> {
+    ctx: Arc<NodeContext<H, D>>,
+    shutdown: Arc<std::sync::atomic::AtomicBool>,
+}
+
+/// Read one length-prefixed frame, rejecting it before allocating if its declared length exceeds
+/// `max_len` — otherwise a peer's 4-byte length prefix alone could force a multi-gigabyte
+/// allocation before a single byte of the body has even been read.
+async fn read_frame<R: AsyncReadExt + Unpin>(stream: &mut R, max_len: usize) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0_u8; 4];
+    stream.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_len {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "frame exceeds maximum size"));
+    }
+    let mut buf = vec![0_u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn encode_frame(msg: &Message) -> Vec<u8> {
+    let body = msg.serialize();
+    let mut framed = Vec::with_capacity(4 + body.len());
+    framed.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&body);
+    framed
+}
+
+impl<H: Host + 'static, D: DataStore + 'static> Node<H, D> {
+    pub async fn new(host: Arc<H>, datastore: Arc<D>, bind_address: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(bind_address).await?;
+        let shutdown = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let advertised_timeout = host.connection_timeout();
+        let peer_table = host.fixed_peers().iter().cloned().map(|a| (a, ms_since_epoch())).collect();
+        let ctx = Arc::new(NodeContext {
+            host,
+            datastore,
+            bind_address,
+            connections: Mutex::new(HashMap::new()),
+            nat_detected: std::sync::atomic::AtomicBool::new(false),
+            advertised_timeout: Mutex::new(advertised_timeout),
+            peer_table: Mutex::new(peer_table),
+            dialing: Mutex::new(std::collections::HashSet::new()),
+        });
+
+        // Accept inbound connections.
+        {
+            let ctx = ctx.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                loop {
+                    if shutdown.load(std::sync::atomic::Ordering::Relaxed) {
+                        return;
+                    }
+                    if let Ok((stream, addr)) = listener.accept().await {
+                        let ctx = ctx.clone();
+                        tokio::spawn(async move {
+                            handle_connection(stream, PeerAddress::Socket(addr), true, false, ctx).await;
+                        });
+                    }
+                }
+            });
+        }
+
+        // Dial fixed peers and gossip-discovered peers that aren't already connected.
+        {
+            let ctx = ctx.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                loop {
+                    if shutdown.load(std::sync::atomic::Ordering::Relaxed) {
+                        return;
+                    }
+                    let candidates: Vec<PeerAddress> =
+                        ctx.host.fixed_peers().iter().cloned().chain(ctx.peer_table.lock().unwrap().keys().cloned()).collect();
+                    for addr in candidates {
+                        if ctx.connections.lock().unwrap().contains_key(&addr) {
+                            continue;
+                        }
+                        // Reserve this address before dialing: the handshake that registers it in
+                        // `connections` runs in a separately spawned task and can take longer than
+                        // this loop's 1-second tick, so without this the next tick would see it
+                        // still missing from `connections` and dial it again.
+                        if !ctx.dialing.lock().unwrap().insert(addr.clone()) {
+                            continue;
+                        }
+                        // Dial in its own task, timing out the connect attempt itself: neither
+                        // TcpStream::connect nor a SOCKS5/Tor proxy handshake has any timeout of
+                        // its own, and without this a single unreachable peer or stalling proxy
+                        // would otherwise block every other candidate behind it in this loop.
+                        let ctx = ctx.clone();
+                        tokio::spawn(async move {
+                            let proxy = ctx.host.proxy_for(&addr);
+                            ctx.host.on_connect_attempt(&addr, proxy.is_some());
+                            let connected = match (&addr, proxy) {
+                                (_, Some(proxy_addr)) => {
+                                    tokio::time::timeout(DIAL_TIMEOUT, connect_via_socks5(proxy_addr, &addr)).await.ok().and_then(|r| r.ok())
+                                }
+                                (PeerAddress::Socket(socket_addr), None) => {
+                                    tokio::time::timeout(DIAL_TIMEOUT, TcpStream::connect(socket_addr)).await.ok().and_then(|r| r.ok())
+                                }
+                                (PeerAddress::Hostname { .. }, None) => None, // Can't resolve a hostname peer without a proxy.
+                            };
+                            if let Some(stream) = connected {
+                                let via_proxy = proxy.is_some();
+                                handle_connection(stream, addr.clone(), false, via_proxy, ctx.clone()).await;
+                            }
+                            ctx.dialing.lock().unwrap().remove(&addr);
+                        });
+                    }
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            });
+        }
+
+        // Evict gossiped peer addresses that haven't been re-confirmed within `gossip_max_age`, so
+        // a peer that goes permanently dark eventually stops being retried. Fixed peers are exempt:
+        // they're configured bootstrap addresses, not gossip, and should always keep being dialed.
+        {
+            let ctx = ctx.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(GOSSIP_INTERVAL).await;
+                    if shutdown.load(std::sync::atomic::Ordering::Relaxed) {
+                        return;
+                    }
+                    let now = ms_since_epoch();
+                    let max_age = ctx.host.gossip_max_age();
+                    let fixed: std::collections::HashSet<&PeerAddress> = ctx.host.fixed_peers().iter().collect();
+                    ctx.peer_table.lock().unwrap().retain(|addr, last_seen_ms| !is_stale_peer(&fixed, addr, *last_seen_ms, now, max_age));
+                }
+            });
+        }
+
+        Ok(Self { ctx, shutdown })
+    }
+
+    /// Whether this node currently believes it's behind NAT, as reported by a connected peer.
+    pub fn nat_detected(&self) -> bool {
+        self.ctx.nat_detected.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub async fn connection_count(&self) -> usize {
+        self.ctx.connections.lock().unwrap().len()
+    }
+
+    pub fn datastore(&self) -> &D {
+        self.ctx.datastore.as_ref()
+    }
+
+    pub fn host(&self) -> &H {
+        self.ctx.host.as_ref()
+    }
+
+    /// A snapshot of every peer address this node currently knows about (configured or gossiped),
+    /// each with its last-seen time in milliseconds since the epoch.
+    pub fn known_peers(&self) -> Vec<(PeerAddress, i64)> {
+        self.ctx.peer_table.lock().unwrap().iter().map(|(a, t)| (a.clone(), *t)).collect()
+    }
+
+    /// Info on every currently live connection, e.g. for a connections/diagnostics listing.
+    pub fn connected_peers(&self) -> Vec<Arc<Mutex<RemoteNodeInfo>>> {
+        self.ctx.connections.lock().unwrap().values().map(|h| h.info.clone()).collect()
+    }
+
+    /// Send `msg` to `peer` and wait for its response, if we're currently connected to that peer.
+    pub async fn request(&self, peer: &PeerAddress, msg: Message) -> Option<Message> {
+        let (outgoing, pending_response) = {
+            let connections = self.ctx.connections.lock().unwrap();
+            let handle = connections.get(peer)?;
+            (handle.outgoing.clone(), handle.pending_response.clone())
+        };
+        request::<D>(&outgoing, &pending_response, msg).await
+    }
+}
+
+impl<H: Host + 'static, D: DataStore + 'static> Drop for Node<H, D> {
+    fn drop(&mut self) {
+        self.shutdown.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+async fn handle_connection<H: Host + 'static, D: DataStore + 'static>(
+    mut stream: TcpStream,
+    addr: PeerAddress,
+    inbound: bool,
+    via_proxy: bool,
+    ctx: Arc<NodeContext<H, D>>,
+) {
+    let max_frame_size = D::MAX_VALUE_SIZE + FRAME_OVERHEAD;
+
+    // Handshake: exchange node names, timeout budgets, our own dialable address, and each side's
+    // view of the other's address.
+    let our_timeout = *ctx.advertised_timeout.lock().unwrap();
+    let our_address = PeerAddress::Socket(ctx.bind_address);
+    let hello = Message::Hello {
+        node_name: ctx.host.name().map(|s| s.to_string()),
+        advertised_timeout_ms: our_timeout.as_millis() as u64,
+        observed_address: Some(addr.clone()),
+        listen_address: Some(our_address.clone()),
+    };
+    if stream.write_all(&encode_frame(&hello)).await.is_err() {
+        return;
+    }
+    let (peer_name, peer_timeout, peer_observed_us, peer_listen_address) = match read_frame(&mut stream, max_frame_size).await {
+        Ok(buf) => match Message::deserialize(&buf) {
+            Some(Message::Hello { node_name, advertised_timeout_ms, observed_address, listen_address }) => {
+                (node_name, Duration::from_millis(advertised_timeout_ms), observed_address, listen_address)
+            }
+            _ => return,
+        },
+        Err(_) => return,
+    };
+
+    // Key this connection by the peer's stable, dialable listen address rather than by `addr`,
+    // which for an inbound connection is just the ephemeral source port of that one TCP stream.
+    // Without this, dialing each other simultaneously (as any two nodes that are each other's
+    // fixed/gossiped peer will) produces two independent, never-merged connections per pair.
+    let peer_key = peer_listen_address.clone().unwrap_or_else(|| addr.clone());
+
+    // Exactly one direction should survive per peer pair: by convention the side with the lesser
+    // address (an arbitrary but globally consistent total order) is the dialer. Both sides derive
+    // this purely from addresses they already know, with no extra coordination, so they agree.
+    let we_are_dialer = our_address < peer_key;
+    let redundant = if inbound { we_are_dialer } else { !we_are_dialer };
+    if redundant {
+        return;
+    }
+
+    // If the peer sees us at a different address than the one we're bound to, we're behind NAT;
+    // shorten the timeout we advertise going forward so intermediate NAT state doesn't expire.
+    let behind_nat = peer_observed_us.map_or(false, |observed| observed != our_address);
+    if behind_nat {
+        ctx.nat_detected.store(true, std::sync::atomic::Ordering::Relaxed);
+        let mut t = ctx.advertised_timeout.lock().unwrap();
+        if *t > NAT_DETECTED_TIMEOUT {
+            *t = NAT_DETECTED_TIMEOUT;
+        }
+    }
+
+    // `peer_timeout` comes straight from the peer's Hello with no floor of its own; without this
+    // clamp, a peer advertising a timeout of (near) zero would drive the interval to (near) zero
+    // and the keepalive task would ping on essentially every tick forever.
+    let negotiated_keepalive_interval = (our_timeout.min(peer_timeout) / 3).max(MIN_KEEPALIVE_INTERVAL);
+
+    let info = Arc::new(Mutex::new(RemoteNodeInfo {
+        node_name: peer_name,
+        remote_address: peer_key.clone(),
+        inbound,
+        initialized: true,
+        via_proxy,
+        latency: None,
+        last_seen_ms: ms_since_epoch(),
+        peer_advertised_timeout: peer_timeout,
+        negotiated_keepalive_interval,
+        nat_detected: behind_nat,
+    }));
+    let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let pending_response = Arc::new(PendingResponse::new());
+    let keepalive = Arc::new(Mutex::new(KeepaliveState { last_activity: Instant::now(), outstanding: None }));
+    let (kill_tx, mut kill_rx) = oneshot::channel::<&'static str>();
+    let kill_tx = Arc::new(Mutex::new(Some(kill_tx)));
+
+    // Check-and-reserve the slot for this peer in a single critical section: two connections to
+    // the same peer (e.g. simultaneous dial and accept) run this function concurrently, and
+    // checking `contains_key` under one lock acquisition then inserting under another left a gap
+    // where both could observe the slot empty and both insert, clobbering one another.
+    {
+        let mut connections = ctx.connections.lock().unwrap();
+        if connections.contains_key(&peer_key) {
+            return;
+        }
+        connections.insert(
+            peer_key.clone(),
+            ConnectionHandle { info: info.clone(), outgoing: outgoing_tx.clone(), pending_response: pending_response.clone() },
+        );
+    }
+    ctx.host.on_connect(&info.lock().unwrap());
+
+    let (mut read_half, mut write_half) = stream.into_split();
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(frame) = outgoing_rx.recv().await {
+            if write_half.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let reader_task = {
+        let ctx = ctx.clone();
+        let outgoing_tx = outgoing_tx.clone();
+        let pending_response = pending_response.clone();
+        let info = info.clone();
+        let keepalive = keepalive.clone();
+        tokio::spawn(async move {
+            loop {
+                let buf = match read_frame(&mut read_half, max_frame_size).await {
+                    Ok(buf) => buf,
+                    Err(_) => return "connection closed".to_string(),
+                };
+                let msg = match Message::deserialize(&buf) {
+                    Some(m) => m,
+                    None => return "protocol error".to_string(),
+                };
+                keepalive.lock().unwrap().last_activity = Instant::now();
+                info.lock().unwrap().last_seen_ms = ms_since_epoch();
+                match msg {
+                    Message::Ping { nonce } => {
+                        let _ = outgoing_tx.send(encode_frame(&Message::Pong { nonce }));
+                    }
+                    Message::Pong { nonce } => {
+                        let mut ka = keepalive.lock().unwrap();
+                        if let Some((outstanding_nonce, sent_at)) = ka.outstanding {
+                            if outstanding_nonce == nonce {
+                                info.lock().unwrap().latency = Some(sent_at.elapsed());
+                                ka.outstanding = None;
+                            }
+                        }
+                    }
+                    Message::ReqGossip => {
+                        let now = ms_since_epoch();
+                        let entries: Vec<(PeerAddress, u64)> = ctx
+                            .peer_table
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .take(GOSSIP_SAMPLE_SIZE)
+                            .map(|(addr, last_seen_ms)| (addr.clone(), now.saturating_sub(*last_seen_ms) as u64))
+                            .collect();
+                        let _ = outgoing_tx.send(encode_frame(&Message::Gossip { entries }));
+                    }
+                    _ if is_request(&msg) => {
+                        let reply = handle_request(ctx.datastore.as_ref(), msg);
+                        let _ = outgoing_tx.send(encode_frame(&reply));
+                    }
+                    _ => {
+                        if let Some(tx) = pending_response.take() {
+                            let _ = tx.send(msg);
+                        }
+                    }
+                }
+            }
+        })
+    };
+
+    // Drive this connection's periodic full-range reconciliation in parallel with the read loop above.
+    let sync_task = {
+        let ctx = ctx.clone();
+        let outgoing_tx = outgoing_tx.clone();
+        let pending_response = pending_response.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SYNC_INTERVAL).await;
+                let start = [0_u8; 64];
+                let end = [0xff_u8; 64];
+                let _ = reconcile_range(ctx.datastore.as_ref(), &outgoing_tx, &pending_response, &start, &end).await;
+            }
+        })
+    };
+
+    // Send a ping once the connection has been idle for `negotiated_keepalive_interval`, and kill the
+    // connection if the matching pong doesn't arrive within `keepalive_timeout`.
+    let keepalive_task = {
+        let ctx = ctx.clone();
+        let outgoing_tx = outgoing_tx.clone();
+        let keepalive = keepalive.clone();
+        let kill_tx = kill_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(KEEPALIVE_TICK).await;
+                let mut ka = keepalive.lock().unwrap();
+                if let Some((_, sent_at)) = ka.outstanding {
+                    if sent_at.elapsed() >= ctx.host.keepalive_timeout() {
+                        if let Some(tx) = kill_tx.lock().unwrap().take() {
+                            let _ = tx.send("keepalive timeout");
+                        }
+                        return;
+                    }
+                } else if ka.last_activity.elapsed() >= negotiated_keepalive_interval {
+                    let mut nonce_buf = [0_u8; 8];
+                    ctx.host.get_secure_random(&mut nonce_buf);
+                    let nonce = u64::from_be_bytes(nonce_buf);
+                    ka.outstanding = Some((nonce, Instant::now()));
+                    drop(ka);
+                    let _ = outgoing_tx.send(encode_frame(&Message::Ping { nonce }));
+                }
+            }
+        })
+    };
+
+    // Periodically trade a sample of known peer addresses with this peer and merge what comes back.
+    let gossip_task = {
+        let ctx = ctx.clone();
+        let outgoing_tx = outgoing_tx.clone();
+        let pending_response = pending_response.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(GOSSIP_INTERVAL).await;
+                let reply = request::<D>(&outgoing_tx, &pending_response, Message::ReqGossip).await;
+                if let Some(Message::Gossip { entries }) = reply {
+                    let now = ms_since_epoch();
+                    let max_age = ctx.host.gossip_max_age();
+                    let newly_discovered = {
+                        let mut table = ctx.peer_table.lock().unwrap();
+                        merge_gossip_entries(&mut table, entries, now, max_age)
+                    };
+                    for (addr, last_seen_ms) in newly_discovered {
+                        ctx.host.on_peer_discovered(&addr, last_seen_ms);
+                    }
+                }
+            }
+        })
+    };
+
+    let reader_abort = reader_task.abort_handle();
+    let closed_reason = tokio::select! {
+        reason = reader_task => reason.unwrap_or_else(|_| "connection closed".to_string()),
+        reason = &mut kill_rx => reason.unwrap_or("keepalive timeout").to_string(),
+    };
+
+    reader_abort.abort();
+    sync_task.abort();
+    keepalive_task.abort();
+    gossip_task.abort();
+    writer_task.abort();
+
+    ctx.connections.lock().unwrap().remove(&peer_key);
+    ctx.host.on_connection_closed(&info.lock().unwrap(), closed_reason);
+}
+
+/// Whether a gossiped peer address should be evicted from the peer table: true if it isn't a
+/// fixed/configured peer and hasn't been seen within `max_age`.
+fn is_stale_peer(fixed: &std::collections::HashSet<&PeerAddress>, addr: &PeerAddress, last_seen_ms: i64, now: i64, max_age: Duration) -> bool {
+    !fixed.contains(addr) && Duration::from_millis(now.saturating_sub(last_seen_ms) as u64) > max_age
+}
+
+/// Merge a batch of gossiped `(address, age_ms)` entries into `table`: entries older than
+/// `max_age` are dropped, and an existing entry is only overwritten by a fresher last-seen time.
+/// Returns the addresses newly added to `table`, paired with their last-seen time, so the caller
+/// can notify about them after releasing whatever lock guards `table`.
+fn merge_gossip_entries(
+    table: &mut HashMap<PeerAddress, i64>,
+    entries: Vec<(PeerAddress, u64)>,
+    now: i64,
+    max_age: Duration,
+) -> Vec<(PeerAddress, i64)> {
+    let mut newly_discovered = Vec::new();
+    for (addr, age_ms) in entries {
+        if Duration::from_millis(age_ms) > max_age {
+            continue;
+        }
+        let last_seen_ms = now.saturating_sub(age_ms as i64);
+        let is_new = !table.contains_key(&addr);
+        let fresher = table.get(&addr).map_or(true, |existing| last_seen_ms > *existing);
+        if fresher {
+            table.insert(addr.clone(), last_seen_ms);
+        }
+        if is_new {
+            newly_discovered.push((addr, last_seen_ms));
+        }
+    }
+    newly_discovered
+}
+
+fn is_request(msg: &Message) -> bool {
+    matches!(
+        msg,
+        Message::ReqRangeHash { .. } | Message::ReqSubRangeHashes { .. } | Message::ReqKeys { .. } | Message::ReqValue { .. }
+    )
+}
+
+fn handle_request<D: DataStore>(datastore: &D, msg: Message) -> Message {
+    match msg {
+        Message::ReqRangeHash { reference_time, key_start, key_end, .. } => {
+            if key_start > key_end {
+                return Message::RangeHash { hash: [0_u8; 64] };
+            }
+            Message::RangeHash { hash: datastore.hash_range(reference_time, &key_start, &key_end) }
+        }
+        Message::ReqSubRangeHashes { reference_time, key_start, key_end, fanout, .. } => {
+            if key_start > key_end {
+                return Message::SubRangeHashes { hashes: Vec::new() };
+            }
+            // `fanout` comes straight off the wire from the peer; clamp it so a malicious value
+            // (e.g. `u32::MAX`) can't make us build and hash an unbounded number of sub-ranges.
+            let fanout = (fanout as usize).clamp(1, D::HASH_RANGE_FANOUT);
+            Message::SubRangeHashes { hashes: sub_range_hashes(datastore, reference_time, &key_start, &key_end, fanout) }
+        }
+        Message::ReqKeys { reference_time, key_start, key_end, .. } => {
+            if key_start > key_end {
+                return Message::Keys { keys: Vec::new() };
+            }
+            let mut keys = Vec::new();
+            datastore.for_each(reference_time, &key_start, &key_end, |k, _| {
+                if let Ok(k) = k.try_into() {
+                    keys.push(k);
+                }
+                true
+            });
+            Message::Keys { keys }
+        }
+        Message::ReqValue { key, .. } => {
+            let value = match datastore.load(datastore.clock(), &key) {
+                LoadResult::Ok(v) => v.as_ref().to_vec(),
+                _ => Vec::new(),
+            };
+            Message::Value { key, value }
+        }
+        other => other,
+    }
+}
+
+/// Split `[key_start, key_end]` into `fanout` equal sub-ranges by key prefix and hash each.
+fn sub_range_hashes<D: DataStore>(datastore: &D, reference_time: i64, key_start: &[u8; 64], key_end: &[u8; 64], fanout: usize) -> Vec<[u8; 64]> {
+    let bounds = split_range(key_start, key_end, fanout);
+    bounds.windows(2).map(|w| datastore.hash_range(reference_time, &w[0], &w[1])).collect()
+}
+
+/// Compute `fanout + 1` boundary keys splitting `[start, end]` into `fanout` equal sub-ranges.
+fn split_range(start: &[u8; 64], end: &[u8; 64], fanout: usize) -> Vec<[u8; 64]> {
+    let mut bounds = Vec::with_capacity(fanout + 1);
+    // Treat the first 8 bytes of the key as a big-endian integer for evenly spaced splitting;
+    // this is precise enough given the 64-byte key space is effectively uniform random.
+    let s = u64::from_be_bytes(start[..8].try_into().unwrap());
+    let e = u64::from_be_bytes(end[..8].try_into().unwrap());
+    let span = e.saturating_sub(s);
+    for i in 0..=fanout {
+        let offset = ((span as u128) * (i as u128) / (fanout as u128)) as u64;
+        let mut k = *start;
+        k[..8].copy_from_slice(&(s.saturating_add(offset)).to_be_bytes());
+        if i > 0 {
+            // Interior boundaries sit one key past the computed split point. Each boundary is both
+            // the inclusive end of one sub-range and the inclusive start of the next, so a key equal
+            // to an interior boundary falls in both adjacent sub-ranges. The last boundary is `end`.
+            let mut adj = k;
+            for byte in adj.iter_mut().rev() {
+                if *byte != 0xff {
+                    *byte += 1;
+                    break;
+                } else {
+                    *byte = 0;
+                }
+            }
+            bounds.push(if i == fanout { *end } else { adj });
+        } else {
+            bounds.push(k);
+        }
+    }
+    bounds
+}
+
+async fn request<D: DataStore>(outgoing: &mpsc::UnboundedSender<Vec<u8>>, pending: &Arc<PendingResponse>, msg: Message) -> Option<Message> {
+    // Held across the whole round trip so a second concurrent caller on this connection blocks
+    // here instead of overwriting `slot` while we're still waiting on our own reply.
+    let _serialize = pending.serialize.lock().await;
+    let (tx, rx) = oneshot::channel();
+    *pending.slot.lock().unwrap() = Some(tx);
+    if outgoing.send(encode_frame(&msg)).is_err() {
+        return None;
+    }
+    tokio::time::timeout(Duration::from_secs(10), rx).await.ok()?.ok()
+}
+
+/// Recursively reconcile `[key_start, key_end]` against a connected peer: compare range hashes,
+/// split and recurse into mismatching sub-ranges, and fetch any keys the peer holds that we don't
+/// once the range is small enough to list directly.
+fn reconcile_range<'a, D: DataStore + 'static>(
+    datastore: &'a D,
+    outgoing: &'a mpsc::UnboundedSender<Vec<u8>>,
+    pending: &'a Arc<PendingResponse>,
+    key_start: &'a [u8; 64],
+    key_end: &'a [u8; 64],
+) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'a>> {
+    Box::pin(async move {
+        let reference_time = datastore.clock();
+        let our_hash = datastore.hash_range(reference_time, key_start, key_end);
+        let reply = request::<D>(
+            outgoing,
+            pending,
+            Message::ReqRangeHash { domain: datastore.domain().to_string(), reference_time, key_start: *key_start, key_end: *key_end },
+        )
+        .await;
+        let their_hash = match reply {
+            Some(Message::RangeHash { hash }) => hash,
+            _ => return,
+        };
+        if their_hash == our_hash {
+            return; // Range already in sync.
+        }
+
+        let count = datastore.count(reference_time, key_start, key_end);
+        if count < D::HASH_RANGE_LEAF_THRESHOLD {
+            let reply = request::<D>(
+                outgoing,
+                pending,
+                Message::ReqKeys { domain: datastore.domain().to_string(), reference_time, key_start: *key_start, key_end: *key_end },
+            )
+            .await;
+            if let Some(Message::Keys { keys }) = reply {
+                let mut have: std::collections::HashSet<[u8; 64]> = std::collections::HashSet::new();
+                datastore.for_each(reference_time, key_start, key_end, |k, _| {
+                    if let Ok(k) = k.try_into() {
+                        have.insert(k);
+                    }
+                    true
+                });
+                for key in keys {
+                    if !have.contains(&key) {
+                        if let Some(Message::Value { value, .. }) =
+                            request::<D>(outgoing, pending, Message::ReqValue { domain: datastore.domain().to_string(), key }).await
+                        {
+                            if !value.is_empty() && value.len() <= D::MAX_VALUE_SIZE {
+                                let _ = datastore.store(&key, &value);
+                            }
+                        }
+                    }
+                }
+            }
+            return;
+        }
+
+        let fanout = D::HASH_RANGE_FANOUT;
+        let reply = request::<D>(
+            outgoing,
+            pending,
+            Message::ReqSubRangeHashes {
+                domain: datastore.domain().to_string(),
+                reference_time,
+                key_start: *key_start,
+                key_end: *key_end,
+                fanout: fanout as u32,
+            },
+        )
+        .await;
+        let their_sub_hashes = match reply {
+            Some(Message::SubRangeHashes { hashes }) => hashes,
+            _ => return,
+        };
+        let bounds = split_range(key_start, key_end, fanout);
+        for (i, their_sub_hash) in their_sub_hashes.into_iter().enumerate() {
+            let sub_start = bounds[i];
+            let sub_end = bounds[i + 1];
+            let our_sub_hash = datastore.hash_range(reference_time, &sub_start, &sub_end);
+            if our_sub_hash != their_sub_hash {
+                reconcile_range(datastore, outgoing, pending, &sub_start, &sub_end).await;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::StoreResult;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn addr(port: u16) -> PeerAddress {
+        PeerAddress::Socket(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)))
+    }
+
+    fn key(first_byte: u8) -> [u8; 64] {
+        let mut k = [0_u8; 64];
+        k[0] = first_byte;
+        k
+    }
+
+    #[test]
+    fn split_range_produces_fanout_plus_one_non_overlapping_boundaries() {
+        let bounds = split_range(&[0_u8; 64], &[0xff_u8; 64], 4);
+        assert_eq!(bounds.len(), 5);
+        assert_eq!(bounds[0], [0_u8; 64]);
+        assert_eq!(bounds[4], [0xff_u8; 64]);
+        // Boundaries must strictly increase, so no sub-range passed to hash_range/for_each runs
+        // backwards; adjacent sub-ranges share only their common boundary key.
+        for w in bounds.windows(2) {
+            assert!(w[0] < w[1]);
+        }
+    }
+
+    #[test]
+    fn split_range_handles_a_single_sub_range() {
+        let start = [0_u8; 64];
+        let end = [0xff_u8; 64];
+        assert_eq!(split_range(&start, &end, 1), vec![start, end]);
+    }
+
+    struct FakeStore {
+        db: std::sync::Mutex<std::collections::BTreeMap<[u8; 64], Vec<u8>>>,
+    }
+
+    impl DataStore for FakeStore {
+        type LoadResultValueType = Vec<u8>;
+        const MAX_VALUE_SIZE: usize = 1024;
+
+        fn clock(&self) -> i64 {
+            0
+        }
+
+        fn domain(&self) -> &str {
+            "test"
+        }
+
+        fn load(&self, _reference_time: i64, key: &[u8]) -> LoadResult<Self::LoadResultValueType> {
+            let k: [u8; 64] = key.try_into().unwrap();
+            self.db.lock().unwrap().get(&k).cloned().map_or(LoadResult::NotFound, LoadResult::Ok)
+        }
+
+        fn store(&self, key: &[u8], value: &[u8]) -> StoreResult {
+            let k: [u8; 64] = key.try_into().unwrap();
+            self.db.lock().unwrap().insert(k, value.to_vec());
+            StoreResult::Ok(0)
+        }
+
+        fn count(&self, _reference_time: i64, key_range_start: &[u8], key_range_end: &[u8]) -> u64 {
+            let s: [u8; 64] = key_range_start.try_into().unwrap();
+            let e: [u8; 64] = key_range_end.try_into().unwrap();
+            self.db.lock().unwrap().range(s..=e).count() as u64
+        }
+
+        fn total_count(&self) -> u64 {
+            self.db.lock().unwrap().len() as u64
+        }
+
+        fn for_each<F: FnMut(&[u8], &[u8]) -> bool>(&self, _reference_time: i64, key_range_start: &[u8], key_range_end: &[u8], mut f: F) {
+            let s: [u8; 64] = key_range_start.try_into().unwrap();
+            let e: [u8; 64] = key_range_end.try_into().unwrap();
+            for (k, v) in self.db.lock().unwrap().range(s..=e) {
+                if !f(k, v) {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sub_range_hashes_differ_between_a_populated_and_an_empty_half() {
+        let store = FakeStore { db: std::sync::Mutex::new(std::collections::BTreeMap::new()) };
+        let _ = store.store(&key(0), b"hello");
+        let hashes = sub_range_hashes(&store, 0, &[0_u8; 64], &key(0xff), 2);
+        assert_eq!(hashes.len(), 2);
+        assert_ne!(hashes[0], hashes[1]);
+        assert_eq!(hashes[1], [0_u8; 64]); // The upper half has no records, so its hash is the zero accumulator.
+    }
+
+    #[test]
+    fn merge_gossip_entries_adds_new_and_reports_it() {
+        let mut table = HashMap::new();
+        let newly_discovered = merge_gossip_entries(&mut table, vec![(addr(1), 1_000)], 10_000, Duration::from_secs(3600));
+        assert_eq!(table.get(&addr(1)), Some(&9_000));
+        assert_eq!(newly_discovered, vec![(addr(1), 9_000)]);
+    }
+
+    #[test]
+    fn merge_gossip_entries_drops_entries_older_than_max_age() {
+        let mut table = HashMap::new();
+        let newly_discovered = merge_gossip_entries(&mut table, vec![(addr(1), 10_000)], 20_000, Duration::from_secs(5));
+        assert!(table.is_empty());
+        assert!(newly_discovered.is_empty());
+    }
+
+    #[test]
+    fn merge_gossip_entries_keeps_the_fresher_of_two_seen_times_without_reporting_it_as_new() {
+        let mut table = HashMap::new();
+        table.insert(addr(1), 5_000);
+        // An older sighting than what we already have must not overwrite it or count as new.
+        let newly_discovered = merge_gossip_entries(&mut table, vec![(addr(1), 9_000)], 10_000, Duration::from_secs(3600));
+        assert_eq!(table.get(&addr(1)), Some(&5_000));
+        assert!(newly_discovered.is_empty());
+
+        // A fresher sighting overwrites it, but it's still not "new" since we already knew the address.
+        let newly_discovered = merge_gossip_entries(&mut table, vec![(addr(1), 1_000)], 10_000, Duration::from_secs(3600));
+        assert_eq!(table.get(&addr(1)), Some(&9_000));
+        assert!(newly_discovered.is_empty());
+    }
+
+    #[test]
+    fn is_stale_peer_ignores_fixed_peers_regardless_of_age() {
+        let a = addr(1);
+        let fixed: std::collections::HashSet<&PeerAddress> = [&a].into_iter().collect();
+        assert!(!is_stale_peer(&fixed, &a, 0, 1_000_000, Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn is_stale_peer_evicts_gossiped_peers_past_max_age() {
+        let fixed: std::collections::HashSet<&PeerAddress> = std::collections::HashSet::new();
+        let a = addr(1);
+        assert!(!is_stale_peer(&fixed, &a, 9_000, 10_000, Duration::from_secs(5)));
+        assert!(is_stale_peer(&fixed, &a, 0, 10_000, Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,478 @@
+//! Wire messages exchanged between connected nodes.
+//!
+//! Every message is framed on the wire as a 4-byte big-endian length prefix followed by
+//! `serialize()`'s output; `deserialize()` is the inverse. Keys and hashes are always the fixed
+//! 64-byte `DataStore` key size.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::address::PeerAddress;
+use crate::utils::{varint_decode, varint_encode};
+
+const TAG_HELLO: u8 = 1;
+const TAG_REQ_RANGE_HASH: u8 = 2;
+const TAG_RANGE_HASH: u8 = 3;
+const TAG_REQ_SUB_RANGE_HASHES: u8 = 4;
+const TAG_SUB_RANGE_HASHES: u8 = 5;
+const TAG_REQ_KEYS: u8 = 6;
+const TAG_KEYS: u8 = 7;
+const TAG_REQ_VALUE: u8 = 8;
+const TAG_VALUE: u8 = 9;
+const TAG_PING: u8 = 10;
+const TAG_PONG: u8 = 11;
+const TAG_REQ_GOSSIP: u8 = 12;
+const TAG_GOSSIP: u8 = 13;
+
+#[derive(Clone, Debug)]
+pub enum Message {
+    /// First message sent on every new connection.
+    Hello {
+        node_name: Option<String>,
+        /// The sender's own connection-timeout budget, in milliseconds; the peer uses the smaller
+        /// of its own and this value to pick a keepalive interval that comfortably beats it.
+        advertised_timeout_ms: u64,
+        /// The address the sender observed this connection coming from, so the peer can notice
+        /// when that differs from the address it believes it's bound to (i.e. it's behind NAT).
+        observed_address: Option<PeerAddress>,
+        /// The address other nodes should dial to reach the sender, i.e. its own configured bind
+        /// address. Unlike the socket address of an inbound connection (an ephemeral source port),
+        /// this gives the peer a stable identity to key the connection by, whichever side dialed.
+        listen_address: Option<PeerAddress>,
+    },
+
+    /// Ask the peer for the commutative hash of every record in `[key_start, key_end]`.
+    ReqRangeHash { domain: String, reference_time: i64, key_start: [u8; 64], key_end: [u8; 64] },
+
+    /// Answer to `ReqRangeHash`.
+    RangeHash { hash: [u8; 64] },
+
+    /// Ask the peer to split `[key_start, key_end]` into `fanout` equal sub-ranges by key prefix
+    /// and return the hash of each.
+    ReqSubRangeHashes { domain: String, reference_time: i64, key_start: [u8; 64], key_end: [u8; 64], fanout: u32 },
+
+    /// Answer to `ReqSubRangeHashes`, one hash per sub-range in order.
+    SubRangeHashes { hashes: Vec<[u8; 64]> },
+
+    /// Ask the peer to list every key (without value) in a leaf-sized range.
+    ReqKeys { domain: String, reference_time: i64, key_start: [u8; 64], key_end: [u8; 64] },
+
+    /// Answer to `ReqKeys`.
+    Keys { keys: Vec<[u8; 64]> },
+
+    /// Ask the peer for the value stored under `key`.
+    ReqValue { domain: String, key: [u8; 64] },
+
+    /// Answer to `ReqValue`; `value` is empty if the peer doesn't have the key.
+    Value { key: [u8; 64], value: Vec<u8> },
+
+    /// Keepalive liveness probe carrying a random nonce that must be echoed back in a `Pong`.
+    Ping { nonce: u64 },
+
+    /// Answer to `Ping`, echoing its nonce exactly.
+    Pong { nonce: u64 },
+
+    /// Ask the peer for a bounded sample of peer addresses it knows about.
+    ReqGossip,
+
+    /// Answer to `ReqGossip`: a bounded sample of `(address, age_ms)`, where `age_ms` is how long
+    /// ago the sender last saw that address, relative to the sender's own clock. The receiver
+    /// reconstitutes a last-seen timestamp on its own clock as `now - age_ms`, so the two sides
+    /// never need synchronized clocks.
+    Gossip { entries: Vec<(PeerAddress, u64)> },
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    varint_encode(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_str(buf: &[u8]) -> Option<(String, usize)> {
+    let (len, n) = varint_decode(buf)?;
+    let len = len as usize;
+    let s = std::str::from_utf8(buf.get(n..n + len)?).ok()?.to_string();
+    Some((s, n + len))
+}
+
+fn write_peer_address_opt(buf: &mut Vec<u8>, addr: &Option<PeerAddress>) {
+    match addr {
+        Some(PeerAddress::Socket(SocketAddr::V4(a))) => {
+            buf.push(4);
+            buf.extend_from_slice(&a.ip().octets());
+            buf.extend_from_slice(&a.port().to_be_bytes());
+        }
+        Some(PeerAddress::Socket(SocketAddr::V6(a))) => {
+            buf.push(6);
+            buf.extend_from_slice(&a.ip().octets());
+            buf.extend_from_slice(&a.port().to_be_bytes());
+        }
+        Some(PeerAddress::Hostname { host, port }) => {
+            buf.push(5);
+            write_str(buf, host);
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+fn read_peer_address_opt(buf: &[u8]) -> Option<(Option<PeerAddress>, usize)> {
+    match *buf.first()? {
+        0 => Some((None, 1)),
+        4 => {
+            let octets: [u8; 4] = buf.get(1..5)?.try_into().ok()?;
+            let port = u16::from_be_bytes(buf.get(5..7)?.try_into().ok()?);
+            Some((Some(PeerAddress::Socket(SocketAddr::new(IpAddr::from(octets), port))), 7))
+        }
+        6 => {
+            let octets: [u8; 16] = buf.get(1..17)?.try_into().ok()?;
+            let port = u16::from_be_bytes(buf.get(17..19)?.try_into().ok()?);
+            Some((Some(PeerAddress::Socket(SocketAddr::new(IpAddr::from(octets), port))), 19))
+        }
+        5 => {
+            let (host, n) = read_str(&buf[1..])?;
+            let port = u16::from_be_bytes(buf.get(1 + n..1 + n + 2)?.try_into().ok()?);
+            Some((Some(PeerAddress::Hostname { host, port }), 1 + n + 2))
+        }
+        _ => None,
+    }
+}
+
+fn write_key(buf: &mut Vec<u8>, k: &[u8; 64]) {
+    buf.extend_from_slice(k);
+}
+
+fn read_key(buf: &[u8]) -> Option<([u8; 64], usize)> {
+    let k: [u8; 64] = buf.get(0..64)?.try_into().ok()?;
+    Some((k, 64))
+}
+
+impl Message {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Message::Hello { node_name, advertised_timeout_ms, observed_address, listen_address } => {
+                buf.push(TAG_HELLO);
+                match node_name {
+                    Some(n) => {
+                        buf.push(1);
+                        write_str(&mut buf, n);
+                    }
+                    None => buf.push(0),
+                }
+                varint_encode(&mut buf, *advertised_timeout_ms);
+                write_peer_address_opt(&mut buf, observed_address);
+                write_peer_address_opt(&mut buf, listen_address);
+            }
+            Message::ReqRangeHash { domain, reference_time, key_start, key_end } => {
+                buf.push(TAG_REQ_RANGE_HASH);
+                write_str(&mut buf, domain);
+                buf.extend_from_slice(&reference_time.to_be_bytes());
+                write_key(&mut buf, key_start);
+                write_key(&mut buf, key_end);
+            }
+            Message::RangeHash { hash } => {
+                buf.push(TAG_RANGE_HASH);
+                write_key(&mut buf, hash);
+            }
+            Message::ReqSubRangeHashes { domain, reference_time, key_start, key_end, fanout } => {
+                buf.push(TAG_REQ_SUB_RANGE_HASHES);
+                write_str(&mut buf, domain);
+                buf.extend_from_slice(&reference_time.to_be_bytes());
+                write_key(&mut buf, key_start);
+                write_key(&mut buf, key_end);
+                varint_encode(&mut buf, *fanout as u64);
+            }
+            Message::SubRangeHashes { hashes } => {
+                buf.push(TAG_SUB_RANGE_HASHES);
+                varint_encode(&mut buf, hashes.len() as u64);
+                for h in hashes {
+                    write_key(&mut buf, h);
+                }
+            }
+            Message::ReqKeys { domain, reference_time, key_start, key_end } => {
+                buf.push(TAG_REQ_KEYS);
+                write_str(&mut buf, domain);
+                buf.extend_from_slice(&reference_time.to_be_bytes());
+                write_key(&mut buf, key_start);
+                write_key(&mut buf, key_end);
+            }
+            Message::Keys { keys } => {
+                buf.push(TAG_KEYS);
+                varint_encode(&mut buf, keys.len() as u64);
+                for k in keys {
+                    write_key(&mut buf, k);
+                }
+            }
+            Message::ReqValue { domain, key } => {
+                buf.push(TAG_REQ_VALUE);
+                write_str(&mut buf, domain);
+                write_key(&mut buf, key);
+            }
+            Message::Value { key, value } => {
+                buf.push(TAG_VALUE);
+                write_key(&mut buf, key);
+                varint_encode(&mut buf, value.len() as u64);
+                buf.extend_from_slice(value);
+            }
+            Message::Ping { nonce } => {
+                buf.push(TAG_PING);
+                buf.extend_from_slice(&nonce.to_be_bytes());
+            }
+            Message::Pong { nonce } => {
+                buf.push(TAG_PONG);
+                buf.extend_from_slice(&nonce.to_be_bytes());
+            }
+            Message::ReqGossip => {
+                buf.push(TAG_REQ_GOSSIP);
+            }
+            Message::Gossip { entries } => {
+                buf.push(TAG_GOSSIP);
+                varint_encode(&mut buf, entries.len() as u64);
+                for (addr, age_ms) in entries {
+                    write_peer_address_opt(&mut buf, &Some(addr.clone()));
+                    varint_encode(&mut buf, *age_ms);
+                }
+            }
+        }
+        buf
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Option<Message> {
+        let tag = *buf.first()?;
+        let buf = &buf[1..];
+        match tag {
+            TAG_HELLO => {
+                let has_name = *buf.first()?;
+                let (node_name, off) = if has_name != 0 {
+                    let (name, n) = read_str(&buf[1..])?;
+                    (Some(name), 1 + n)
+                } else {
+                    (None, 1)
+                };
+                let (advertised_timeout_ms, n) = varint_decode(&buf[off..])?;
+                let (observed_address, n2) = read_peer_address_opt(&buf[off + n..])?;
+                let (listen_address, _) = read_peer_address_opt(&buf[off + n + n2..])?;
+                Some(Message::Hello { node_name, advertised_timeout_ms, observed_address, listen_address })
+            }
+            TAG_REQ_RANGE_HASH => {
+                let (domain, n0) = read_str(buf)?;
+                let reference_time = i64::from_be_bytes(buf.get(n0..n0 + 8)?.try_into().ok()?);
+                let (key_start, n1) = read_key(&buf[n0 + 8..])?;
+                let (key_end, _) = read_key(&buf[n0 + 8 + n1..])?;
+                Some(Message::ReqRangeHash { domain, reference_time, key_start, key_end })
+            }
+            TAG_RANGE_HASH => {
+                let (hash, _) = read_key(buf)?;
+                Some(Message::RangeHash { hash })
+            }
+            TAG_REQ_SUB_RANGE_HASHES => {
+                let (domain, n0) = read_str(buf)?;
+                let reference_time = i64::from_be_bytes(buf.get(n0..n0 + 8)?.try_into().ok()?);
+                let (key_start, n1) = read_key(&buf[n0 + 8..])?;
+                let (key_end, n2) = read_key(&buf[n0 + 8 + n1..])?;
+                let (fanout, _) = varint_decode(&buf[n0 + 8 + n1 + n2..])?;
+                Some(Message::ReqSubRangeHashes { domain, reference_time, key_start, key_end, fanout: fanout as u32 })
+            }
+            TAG_SUB_RANGE_HASHES => {
+                let (count, mut off) = varint_decode(buf)?;
+                let mut hashes = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let (h, n) = read_key(&buf[off..])?;
+                    hashes.push(h);
+                    off += n;
+                }
+                Some(Message::SubRangeHashes { hashes })
+            }
+            TAG_REQ_KEYS => {
+                let (domain, n0) = read_str(buf)?;
+                let reference_time = i64::from_be_bytes(buf.get(n0..n0 + 8)?.try_into().ok()?);
+                let (key_start, n1) = read_key(&buf[n0 + 8..])?;
+                let (key_end, _) = read_key(&buf[n0 + 8 + n1..])?;
+                Some(Message::ReqKeys { domain, reference_time, key_start, key_end })
+            }
+            TAG_KEYS => {
+                let (count, mut off) = varint_decode(buf)?;
+                let mut keys = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let (k, n) = read_key(&buf[off..])?;
+                    keys.push(k);
+                    off += n;
+                }
+                Some(Message::Keys { keys })
+            }
+            TAG_REQ_VALUE => {
+                let (domain, n0) = read_str(buf)?;
+                let (key, _) = read_key(&buf[n0..])?;
+                Some(Message::ReqValue { domain, key })
+            }
+            TAG_VALUE => {
+                let (key, n0) = read_key(buf)?;
+                let (len, n1) = varint_decode(&buf[n0..])?;
+                let value = buf.get(n0 + n1..n0 + n1 + len as usize)?.to_vec();
+                Some(Message::Value { key, value })
+            }
+            TAG_PING => {
+                let nonce = u64::from_be_bytes(buf.get(0..8)?.try_into().ok()?);
+                Some(Message::Ping { nonce })
+            }
+            TAG_PONG => {
+                let nonce = u64::from_be_bytes(buf.get(0..8)?.try_into().ok()?);
+                Some(Message::Pong { nonce })
+            }
+            TAG_REQ_GOSSIP => Some(Message::ReqGossip),
+            TAG_GOSSIP => {
+                let (count, mut off) = varint_decode(buf)?;
+                let mut entries = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let (addr, n0) = read_peer_address_opt(&buf[off..])?;
+                    let addr = addr?;
+                    let (age_ms, n1) = varint_decode(&buf[off + n0..])?;
+                    entries.push((addr, age_ms));
+                    off += n0 + n1;
+                }
+                Some(Message::Gossip { entries })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    fn roundtrip(msg: Message) -> Message {
+        Message::deserialize(&msg.serialize()).expect("deserialize should recover what serialize produced")
+    }
+
+    #[test]
+    fn hello_roundtrips_with_all_fields_set() {
+        let msg = Message::Hello {
+            node_name: Some("node-a".to_string()),
+            advertised_timeout_ms: 300_000,
+            observed_address: Some(PeerAddress::Socket(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1234)))),
+            listen_address: Some(PeerAddress::Socket(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 4321, 0, 0)))),
+        };
+        match roundtrip(msg.clone()) {
+            Message::Hello { node_name, advertised_timeout_ms, observed_address, listen_address } => {
+                let Message::Hello {
+                    node_name: orig_name,
+                    advertised_timeout_ms: orig_timeout,
+                    observed_address: orig_observed,
+                    listen_address: orig_listen,
+                } = msg
+                else {
+                    unreachable!()
+                };
+                assert_eq!(node_name, orig_name);
+                assert_eq!(advertised_timeout_ms, orig_timeout);
+                assert_eq!(observed_address, orig_observed);
+                assert_eq!(listen_address, orig_listen);
+            }
+            other => panic!("expected Hello, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hello_roundtrips_with_all_optional_fields_absent() {
+        let msg = Message::Hello { node_name: None, advertised_timeout_ms: 0, observed_address: None, listen_address: None };
+        match roundtrip(msg) {
+            Message::Hello { node_name, observed_address, listen_address, .. } => {
+                assert_eq!(node_name, None);
+                assert_eq!(observed_address, None);
+                assert_eq!(listen_address, None);
+            }
+            other => panic!("expected Hello, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hostname_peer_address_roundtrips() {
+        let msg = Message::Hello {
+            node_name: None,
+            advertised_timeout_ms: 0,
+            observed_address: None,
+            listen_address: Some(PeerAddress::Hostname { host: "example.onion".to_string(), port: 443 }),
+        };
+        match roundtrip(msg) {
+            Message::Hello { listen_address: Some(PeerAddress::Hostname { host, port }), .. } => {
+                assert_eq!(host, "example.onion");
+                assert_eq!(port, 443);
+            }
+            other => panic!("expected Hello with a Hostname listen_address, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn req_range_hash_roundtrips() {
+        let msg = Message::ReqRangeHash { domain: "d".to_string(), reference_time: -1, key_start: [1_u8; 64], key_end: [2_u8; 64] };
+        match roundtrip(msg) {
+            Message::ReqRangeHash { domain, reference_time, key_start, key_end } => {
+                assert_eq!(domain, "d");
+                assert_eq!(reference_time, -1);
+                assert_eq!(key_start, [1_u8; 64]);
+                assert_eq!(key_end, [2_u8; 64]);
+            }
+            other => panic!("expected ReqRangeHash, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keys_and_sub_range_hashes_roundtrip_with_multiple_entries() {
+        let keys = Message::Keys { keys: vec![[1_u8; 64], [2_u8; 64], [3_u8; 64]] };
+        match roundtrip(keys) {
+            Message::Keys { keys } => assert_eq!(keys, vec![[1_u8; 64], [2_u8; 64], [3_u8; 64]]),
+            other => panic!("expected Keys, got {:?}", other),
+        }
+
+        let hashes = Message::SubRangeHashes { hashes: vec![[9_u8; 64], [8_u8; 64]] };
+        match roundtrip(hashes) {
+            Message::SubRangeHashes { hashes } => assert_eq!(hashes, vec![[9_u8; 64], [8_u8; 64]]),
+            other => panic!("expected SubRangeHashes, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn value_roundtrips_including_an_empty_value() {
+        let msg = Message::Value { key: [7_u8; 64], value: Vec::new() };
+        match roundtrip(msg) {
+            Message::Value { key, value } => {
+                assert_eq!(key, [7_u8; 64]);
+                assert!(value.is_empty());
+            }
+            other => panic!("expected Value, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn gossip_roundtrips_with_mixed_address_kinds() {
+        let msg = Message::Gossip {
+            entries: vec![
+                (PeerAddress::Socket(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 80))), 0),
+                (PeerAddress::Hostname { host: "peer.onion".to_string(), port: 9050 }, 123_456),
+            ],
+        };
+        match roundtrip(msg) {
+            Message::Gossip { entries } => {
+                assert_eq!(entries.len(), 2);
+                assert_eq!(entries[1].1, 123_456);
+            }
+            other => panic!("expected Gossip, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ping_pong_and_req_gossip_roundtrip() {
+        assert!(matches!(roundtrip(Message::Ping { nonce: 42 }), Message::Ping { nonce: 42 }));
+        assert!(matches!(roundtrip(Message::Pong { nonce: 42 }), Message::Pong { nonce: 42 }));
+        assert!(matches!(roundtrip(Message::ReqGossip), Message::ReqGossip));
+    }
+
+    #[test]
+    fn deserialize_rejects_truncated_and_unknown_input() {
+        assert!(Message::deserialize(&[]).is_none());
+        assert!(Message::deserialize(&[0xff]).is_none()); // Unknown tag.
+        let full = Message::Ping { nonce: 1 }.serialize();
+        assert!(Message::deserialize(&full[..full.len() - 1]).is_none()); // Truncated body.
+    }
+}
@@ -0,0 +1,77 @@
+//! A minimal SOCKS5 client (RFC 1928) sufficient to dial a peer through a local proxy such as a
+//! Tor daemon, including by hostname so `.onion` addresses never need to resolve locally.
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::address::PeerAddress;
+
+/// Connect to `proxy` and ask it to relay a connection to `target`, returning the resulting stream.
+pub async fn connect_via_socks5(proxy: SocketAddr, target: &PeerAddress) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    // Greeting: SOCKS5, one auth method (no authentication required).
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut reply = [0_u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 || reply[1] != 0x00 {
+        return Err(io::Error::new(io::ErrorKind::Other, "SOCKS5 proxy rejected our auth method"));
+    }
+
+    // CONNECT request.
+    let mut req = vec![0x05, 0x01, 0x00];
+    match target {
+        PeerAddress::Socket(SocketAddr::V4(a)) => {
+            req.push(0x01);
+            req.extend_from_slice(&a.ip().octets());
+            req.extend_from_slice(&a.port().to_be_bytes());
+        }
+        PeerAddress::Socket(SocketAddr::V6(a)) => {
+            req.push(0x04);
+            req.extend_from_slice(&a.ip().octets());
+            req.extend_from_slice(&a.port().to_be_bytes());
+        }
+        PeerAddress::Hostname { host, port } => {
+            if host.len() > 255 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "hostname too long for SOCKS5"));
+            }
+            req.push(0x03);
+            req.push(host.len() as u8);
+            req.extend_from_slice(host.as_bytes());
+            req.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    stream.write_all(&req).await?;
+
+    let mut head = [0_u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != 0x05 {
+        return Err(io::Error::new(io::ErrorKind::Other, "not a SOCKS5 reply"));
+    }
+    if head[1] != 0x00 {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("SOCKS5 proxy refused CONNECT (code {})", head[1])));
+    }
+    // Discard the bound address the proxy reports back; we don't need it.
+    match head[3] {
+        0x01 => {
+            let mut rest = [0_u8; 4 + 2];
+            stream.read_exact(&mut rest).await?;
+        }
+        0x03 => {
+            let mut len = [0_u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut rest = vec![0_u8; len[0] as usize + 2];
+            stream.read_exact(&mut rest).await?;
+        }
+        0x04 => {
+            let mut rest = [0_u8; 16 + 2];
+            stream.read_exact(&mut rest).await?;
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::Other, "unrecognized SOCKS5 address type")),
+    }
+
+    Ok(stream)
+}
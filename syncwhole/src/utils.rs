@@ -0,0 +1,45 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the Unix epoch, used for record timestamps and connection bookkeeping.
+pub fn ms_since_epoch() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+/// Fold `b` into `a` with XOR, the combinator used to build commutative digests of a key range.
+pub fn xor_into(a: &mut [u8; 64], b: &[u8; 64]) {
+    for i in 0..64 {
+        a[i] ^= b[i];
+    }
+}
+
+/// Write `v` as a LEB128 variable-length unsigned integer.
+pub fn varint_encode(buf: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let mut b = (v & 0x7f) as u8;
+        v >>= 7;
+        if v != 0 {
+            b |= 0x80;
+        }
+        buf.push(b);
+        if v == 0 {
+            break;
+        }
+    }
+}
+
+/// Read a LEB128 variable-length unsigned integer, returning the value and bytes consumed.
+pub fn varint_decode(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut v: u64 = 0;
+    let mut shift: u32 = 0;
+    for (i, b) in buf.iter().enumerate() {
+        v |= ((b & 0x7f) as u64) << shift;
+        if (b & 0x80) == 0 {
+            return Some((v, i + 1));
+        }
+        shift += 7;
+        if shift >= 64 {
+            return None;
+        }
+    }
+    None
+}